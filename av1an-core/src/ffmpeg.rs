@@ -143,87 +143,325 @@ pub fn has_audio(file: &Path) -> bool {
   ictx.streams().best(MediaType::Audio).is_some()
 }
 
-pub fn get_channel_layout_float(stream: &ffmpeg::Stream<'_>) -> f32 {
-  let layout_bits: u64 = unsafe { (*stream.parameters().as_ptr()).ch_layout.u.mask };
-  let channels: i32 = unsafe { (*stream.parameters().as_ptr()).ch_layout.nb_channels };
-
-  match ChannelLayout::from_bits(layout_bits) {
-    Some(layout) => {
-      return match layout {
-        ChannelLayout::_2POINT1 | ChannelLayout::_2_1 => 2.1,
-        ChannelLayout::_2_2 => 2.2,
-        ChannelLayout::_3POINT1 => 3.1,
-        ChannelLayout::_4POINT1 => 4.1,
-        ChannelLayout::_5POINT1 | ChannelLayout::_5POINT1_BACK => 5.1,
-        ChannelLayout::_6POINT1 | ChannelLayout::_6POINT1_FRONT | ChannelLayout::_6POINT1_BACK => 6.1,
-        ChannelLayout::_7POINT1 | ChannelLayout::_7POINT1_WIDE | ChannelLayout::_7POINT1_WIDE_BACK => 7.1,
-        _ => channels as f32
-      };
-    },
-    None => {
-      return match channels {
-        3 => 2.1,
-        6 => 5.1,
-        8 => 7.1,
-        _ => channels as f32
-      };
-    },
+/// A channel layout described via FFmpeg's modern `AVChannelLayout`.
+#[derive(Debug, Clone)]
+pub struct ChannelLayoutInfo {
+  /// Canonical name FFmpeg gives this layout (e.g. `"5.1"`).
+  pub name: String,
+  pub channels: i32,
+  pub has_lfe: bool,
+  /// Per-channel position name (e.g. `"FL"`, `"LFE"`), in stream order.
+  pub channel_labels: Vec<String>,
+}
+
+/// Describes a stream's channel layout via `AVChannelLayout`'s `order`
+/// field, instead of assuming every layout is mask-based.
+pub fn describe_channel_layout(stream: &ffmpeg::Stream<'_>) -> ChannelLayoutInfo {
+  unsafe {
+    let params = stream.parameters().as_ptr();
+    let layout = &(*params).ch_layout;
+
+    let has_lfe = match layout.order {
+      ffmpeg_sys_next::AVChannelOrder::AV_CHANNEL_ORDER_NATIVE => {
+        layout.u.mask & (ffmpeg_sys_next::AV_CH_LOW_FREQUENCY as u64) != 0
+      },
+      ffmpeg_sys_next::AVChannelOrder::AV_CHANNEL_ORDER_CUSTOM => {
+        let channels = std::slice::from_raw_parts(layout.u.map, layout.nb_channels as usize);
+        channels
+          .iter()
+          .any(|channel| channel.id == ffmpeg_sys_next::AVChannel::AV_CHAN_LOW_FREQUENCY)
+      },
+      // AV_CHANNEL_ORDER_UNSPEC and ambisonic orders carry no per-channel
+      // position info to inspect.
+      _ => false,
+    };
+
+    let mut name_buf = [0i8; 128];
+    let written = ffmpeg_sys_next::av_channel_layout_describe(
+      layout as *const _ as *mut _,
+      name_buf.as_mut_ptr(),
+      name_buf.len(),
+    );
+
+    let name = if written > 0 {
+      std::ffi::CStr::from_ptr(name_buf.as_ptr()).to_string_lossy().into_owned()
+    } else {
+      format!("{} channels", layout.nb_channels)
+    };
+
+    let channel_labels = (0..layout.nb_channels as u32)
+      .map(|index| {
+        let channel = ffmpeg_sys_next::av_channel_layout_channel_from_index(layout as *const _, index);
+
+        let mut label_buf = [0i8; 16];
+        let written = ffmpeg_sys_next::av_channel_name(label_buf.as_mut_ptr(), label_buf.len(), channel);
+
+        if written > 0 {
+          std::ffi::CStr::from_ptr(label_buf.as_ptr())
+            .to_string_lossy()
+            .to_uppercase()
+        } else {
+          format!("CH{index}")
+        }
+      })
+      .collect();
+
+    ChannelLayoutInfo { name, channels: layout.nb_channels, has_lfe, channel_labels }
+  }
+}
+
+/// Opus VBR bitrate heuristic: `128 * (layout_points / 2) ^ 0.75`.
+pub fn opus_bitrate_for(layout: &ChannelLayoutInfo) -> usize {
+  let mains = if layout.has_lfe { layout.channels - 1 } else { layout.channels };
+  let layout_points = mains as f32 + if layout.has_lfe { 0.1 } else { 0.0 };
+
+  (128.0 * (layout_points / 2.0).powf(0.75)).round() as usize
+}
+
+/// Opus only accepts 48kHz input; anything else must be resampled first.
+const OPUS_SAMPLE_RATE: u32 = 48_000;
+
+/// Pulls every frame the decoder currently has buffered through `resampler`
+/// and `encoder`, writing finished packets to `octx`.
+fn drain_decoder(
+  decoder: &mut ffmpeg::decoder::Audio,
+  resampler: &mut ffmpeg::software::resampling::Context,
+  encoder: &mut ffmpeg::encoder::Audio,
+  octx: &mut ffmpeg::format::context::Output,
+  mono_channel: Option<i32>,
+) -> Result<(), ffmpeg::Error> {
+  let mut decoded = ffmpeg::frame::Audio::empty();
+
+  while decoder.receive_frame(&mut decoded).is_ok() {
+    let source = match mono_channel {
+      Some(channel) => extract_mono_channel(&decoded, channel),
+      None => decoded.clone(),
+    };
+
+    let mut resampled = ffmpeg::frame::Audio::empty();
+    resampler.run(&source, &mut resampled)?;
+
+    encoder.send_frame(&resampled)?;
+    drain_encoder(encoder, octx)?;
+  }
+
+  Ok(())
+}
+
+/// Pulls every packet the encoder currently has buffered and writes it to
+/// `octx`'s (sole) output stream.
+fn drain_encoder(
+  encoder: &mut ffmpeg::encoder::Audio,
+  octx: &mut ffmpeg::format::context::Output,
+) -> Result<(), ffmpeg::Error> {
+  let mut encoded = ffmpeg::Packet::empty();
+
+  while encoder.receive_packet(&mut encoded).is_ok() {
+    encoded.set_stream(0);
+    encoded.write_interleaved(octx)?;
+  }
+
+  Ok(())
+}
+
+/// Builds a mono frame containing only `channel` of `frame`. Used to realize
+/// the per-channel `mono_streams` split without an external `pan` filter.
+fn extract_mono_channel(frame: &ffmpeg::frame::Audio, channel: i32) -> ffmpeg::frame::Audio {
+  let mut mono = ffmpeg::frame::Audio::new(frame.format(), frame.samples(), ChannelLayout::MONO);
+  mono.set_rate(frame.rate());
+
+  if frame.is_planar() {
+    unsafe {
+      std::ptr::copy_nonoverlapping(
+        frame.data(channel as usize).as_ptr(),
+        mono.data_mut(0).as_mut_ptr(),
+        mono.data(0).len(),
+      );
+    }
+  } else {
+    // Packed layout: all channels are interleaved in plane 0, so the wanted
+    // channel has to be picked out sample by sample instead of copied whole.
+    let bytes_per_sample = frame.format().bytes();
+    let stride = bytes_per_sample * frame.channels() as usize;
+    let channel_offset = bytes_per_sample * channel as usize;
+
+    let src = frame.data(0);
+    let dst = mono.data_mut(0);
+
+    for sample in 0..frame.samples() {
+      let src_start = sample * stride + channel_offset;
+      let dst_start = sample * bytes_per_sample;
+      dst[dst_start..dst_start + bytes_per_sample].copy_from_slice(&src[src_start..src_start + bytes_per_sample]);
+    }
+  }
+
+  mono
+}
+
+/// Decodes audio `stream_index` of `input` and re-encodes it to opus,
+/// writing the result to `out_path`. If `mono_channel` is set, only that
+/// channel of the source stream is kept.
+fn transcode_stream_to_opus(
+  input: &Path,
+  stream_index: usize,
+  bitrate: usize,
+  mono_channel: Option<i32>,
+  out_path: &Path,
+) -> Result<(), ffmpeg::Error> {
+  let mut ictx = ffmpeg::format::input(&input)?;
+  let stream = ictx.stream(stream_index).ok_or(StreamNotFound)?;
+  let mut decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+    .decoder()
+    .audio()?;
+
+  let out_channel_layout = mono_channel.map_or(decoder.channel_layout(), |_| ChannelLayout::MONO);
+  let out_format = ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Packed);
+
+  let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::OPUS).ok_or(ffmpeg::Error::EncoderNotFound)?;
+  let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+    .encoder()
+    .audio()?;
+  encoder.set_rate(OPUS_SAMPLE_RATE as i32);
+  encoder.set_channel_layout(out_channel_layout);
+  encoder.set_format(out_format);
+  encoder.set_bit_rate(bitrate * 1_000);
+  let mut encoder = encoder.open_as(codec)?;
+
+  // drain_decoder feeds the resampler frames from extract_mono_channel (tagged
+  // ChannelLayout::MONO) whenever mono_channel is set, so the resampler's
+  // source layout has to match that, not the original multichannel decoder layout.
+  let in_channel_layout = mono_channel.map_or(decoder.channel_layout(), |_| ChannelLayout::MONO);
+
+  let mut resampler = ffmpeg::software::resampling::Context::get(
+    decoder.format(),
+    in_channel_layout,
+    decoder.rate(),
+    encoder.format(),
+    out_channel_layout,
+    OPUS_SAMPLE_RATE,
+  )?;
+
+  let mut octx = ffmpeg::format::output(&out_path)?;
+  {
+    let mut ost = octx.add_stream(codec)?;
+    ost.set_parameters(&encoder);
+  }
+  octx.write_header()?;
+
+  let stream_index = stream.index();
+  for (packet_stream, packet) in ictx.packets() {
+    if packet_stream.index() != stream_index {
+      continue;
+    }
+
+    decoder.send_packet(&packet)?;
+    drain_decoder(&mut decoder, &mut resampler, &mut encoder, &mut octx, mono_channel)?;
   }
+
+  decoder.send_eof()?;
+  drain_decoder(&mut decoder, &mut resampler, &mut encoder, &mut octx, mono_channel)?;
+
+  // swresample keeps its own internal FIFO, so there can still be samples
+  // buffered there once the decoder is drained (always possible when
+  // resampling, i.e. whenever the source isn't already 48kHz) — flush it
+  // before telling the encoder there's no more input.
+  loop {
+    let mut flushed = ffmpeg::frame::Audio::empty();
+    if resampler.flush(&mut flushed)?.is_none() || flushed.samples() == 0 {
+      break;
+    }
+
+    encoder.send_frame(&flushed)?;
+    drain_encoder(&mut encoder, &mut octx)?;
+  }
+
+  encoder.send_eof()?;
+  drain_encoder(&mut encoder, &mut octx)?;
+
+  octx.write_trailer()?;
+
+  Ok(())
 }
 
-pub fn handle_opus(input: &Path, merge_with: &Path, output: &Path, temp: &Path) {
+/// Encodes every audio stream to opus and merges the results (plus
+/// subtitles) back into `output`. If `mono_streams` is set, multichannel
+/// tracks are split into labeled mono streams instead.
+pub fn handle_opus(input: &Path, merge_with: &Path, output: &Path, temp: &Path, mono_streams: bool) {
   let ictx = ffmpeg::format::input(&input).unwrap();
 
   if !temp.join("audio").exists() {
     std::fs::create_dir(temp.join("audio")).expect("Failed to create audio folder");
   }
 
-  let audio_data = ictx
-    .streams()
-    .filter(|f| f.parameters().medium() == media::Type::Audio)
-    .fold(Vec::new(), |mut vec, stream| {
-      let layout = get_channel_layout_float(&stream);
-      let bitrate = (128.0 * (layout / 2.0).powf(0.75)).round() as usize;
-
-      let ffmpeg = Command::new("ffmpeg")
-        .args(["-hide_banner", "-v", "quiet", "-i"])
-        .arg(input.to_str().unwrap())
-        .args(["-vn", "-sn", "-dn", "-map"])
-        .arg(format!("0:{}", stream.index()))
-        .args(["-map_metadata".to_owned(), format!("0:s:{}", stream.index())])
-        .args(["-f", "flac", "-"])
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("ffmpeg failed to start");
-
-      let mut opusenc = Command::new("opusenc")
-        .args(["--quiet", "--vbr", "--bitrate"])
-        .arg(format!("{bitrate}K"))
-        .arg("-")
-        .arg(format!("{}/audio/{}.opus", temp.to_string_lossy(), stream.index()))
-        .stdin(Stdio::from(ffmpeg.stdout.unwrap()))
-        .spawn()
-        .expect("opusenc failed to start");
-
-      opusenc.wait().expect("Opusenc crashed");
-
-      vec.push(
-        stream.index()
-      );
+  let mut audio_data: Vec<(PathBuf, Option<String>)> = Vec::new();
+
+  for stream in ictx.streams().filter(|f| f.parameters().medium() == media::Type::Audio) {
+    let layout = describe_channel_layout(&stream);
+    let bitrate = opus_bitrate_for(&layout);
+    let channels = layout.channels;
+    let stream_index = stream.index();
+
+    if mono_streams && channels > 1 {
+      // Each split-out stream is mono, so it needs a mono-appropriate
+      // bitrate, not the bitrate computed for the original multichannel
+      // track (reusing that would encode every mono stream at several times
+      // the bitrate it actually needs, e.g. 258 vs 76 kbps for 5.1).
+      let mono_bitrate = opus_bitrate_for(&ChannelLayoutInfo {
+        name: "mono".to_owned(),
+        channels: 1,
+        has_lfe: false,
+        channel_labels: Vec::new(),
+      });
+
+      for channel_index in 0..channels {
+        let label = layout
+          .channel_labels
+          .get(channel_index as usize)
+          .cloned()
+          .unwrap_or_else(|| format!("CH{channel_index}"));
+        let out_path = PathBuf::from(format!(
+          "{}/audio/{}_{}.opus",
+          temp.to_string_lossy(),
+          stream_index,
+          channel_index
+        ));
+
+        if let Err(e) = transcode_stream_to_opus(input, stream_index, mono_bitrate, Some(channel_index), &out_path) {
+          warn!("Failed to transcode channel {channel_index} of audio stream {stream_index}: {e}");
+          continue;
+        }
+
+        audio_data.push((out_path, Some(label)));
+      }
+    } else {
+      let out_path = PathBuf::from(format!("{}/audio/{}.opus", temp.to_string_lossy(), stream_index));
 
-      vec
-    });
+      if let Err(e) = transcode_stream_to_opus(input, stream_index, bitrate, None, &out_path) {
+        warn!("Failed to transcode audio stream {stream_index}: {e}");
+        continue;
+      }
 
-  let (input_args, map_args, map_counter) = audio_data
-    .iter()
-    .fold((Vec::new(), Vec::new(), 0usize), |(mut input_args, mut map_args, mut c), a| {
-      input_args.push(format!("-i"));
-      input_args.push(format!("{}/audio/{}.opus", temp.to_string_lossy(), a));
-      map_args.push(format!("-map"));
-      map_args.push(format!("{}", c));
+      audio_data.push((out_path, None));
+    }
+  }
+
+  let (input_args, map_args, metadata_args, map_counter) = audio_data.iter().fold(
+    (Vec::new(), Vec::new(), Vec::new(), 0usize),
+    |(mut input_args, mut map_args, mut metadata_args, mut c), (path, label)| {
+      input_args.push("-i".to_owned());
+      input_args.push(path.to_string_lossy().into_owned());
+      map_args.push("-map".to_owned());
+      map_args.push(format!("{c}"));
+      if let Some(label) = label {
+        metadata_args.push(format!("-metadata:s:a:{c}"));
+        metadata_args.push(format!("handler_name={label}"));
+        metadata_args.push(format!("-metadata:s:a:{c}"));
+        metadata_args.push(format!("title={label}"));
+      }
       c += 1;
-      (input_args, map_args, c)
-    });
+      (input_args, map_args, metadata_args, c)
+    },
+  );
 
   let mut ffmpeg_merge = Command::new("ffmpeg")
     .args(["-y", "-hide_banner", "-v", "quiet"])
@@ -233,6 +471,7 @@ pub fn handle_opus(input: &Path, merge_with: &Path, output: &Path, temp: &Path)
     .args(["-map 0:s"]) // Only map the subtitle streams
     .args(&map_args)
     .args(["-map".to_owned(), format!("{}", map_counter)])
+    .args(&metadata_args)
     .args(["-c", "copy"])
     .arg(output.to_str().unwrap())
     .spawn()
@@ -241,24 +480,47 @@ pub fn handle_opus(input: &Path, merge_with: &Path, output: &Path, temp: &Path)
   ffmpeg_merge.wait().expect("ffmpeg crashed while merging");
 }
 
-/// Encodes the audio using FFmpeg, blocking the current thread.
-///
-/// This function returns `Some(output)` if the audio exists and the audio
-/// successfully encoded, or `None` otherwise.
+/// Selects how [`encode_audio`] re-encodes and containers the source audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioEncodeMode {
+  /// Keep/re-encode using the caller-supplied `audio_params`, written to a
+  /// matroska container. The default (e.g. `-c copy`, or a lossy codec the
+  /// caller chose).
+  Mkv,
+  /// Re-encode every track to opus in a matroska container. `mono_streams`
+  /// additionally splits each multichannel track into labeled mono streams.
+  Opus { mono_streams: bool },
+  /// Re-encode every track to FLAC and mux losslessly into a fragmented MP4
+  /// container (`fLaC` sample entry + `dfLa` `STREAMINFO` box) instead of
+  /// matroska, for players that only accept MP4.
+  FlacMp4,
+}
+
+impl Default for AudioEncodeMode {
+  /// Keeps tracks intact (`Mkv`), so enabling opus/FLAC re-encoding is
+  /// always an explicit opt-in.
+  fn default() -> Self {
+    Self::Mkv
+  }
+}
+
+/// Encodes the audio using FFmpeg, blocking the current thread. Returns
+/// `Some(output)` on success, `None` if there's no audio or encoding failed.
 #[must_use]
 pub fn encode_audio<S: AsRef<OsStr>>(
   input: impl AsRef<Path>,
   temp: impl AsRef<Path>,
-  opus_mode: bool,
+  mode: AudioEncodeMode,
   audio_params: &[S],
 ) -> Option<PathBuf> {
   let input = input.as_ref();
   let temp = temp.as_ref();
 
   if has_audio(input) {
-    let audio_file = match opus_mode {
-        true => Path::new(temp).join("misc.mkv"),
-        false => Path::new(temp).join("audio.mkv"),
+    let audio_file = match mode {
+        AudioEncodeMode::Opus { .. } => Path::new(temp).join("misc.mkv"),
+        AudioEncodeMode::FlacMp4 => Path::new(temp).join("audio.mp4"),
+        AudioEncodeMode::Mkv => Path::new(temp).join("audio.mkv"),
     };
     let mut encode_audio = Command::new("ffmpeg");
 
@@ -279,11 +541,18 @@ pub fn encode_audio<S: AsRef<OsStr>>(
       "copy"
     ]);
 
-    match opus_mode {
-        true => {encode_audio.args(["-map", "0:a:0"])}, // We need one audio track to keep the subtitles in sync.
-        false => encode_audio.args(audio_params),
+    match mode {
+        // We need one audio track to keep the subtitles in sync.
+        AudioEncodeMode::Opus { .. } => { encode_audio.args(["-map", "0:a:0"]); },
+        AudioEncodeMode::FlacMp4 => {
+          // MP4 can't carry subtitle (ASS/SSA/SRT/PGS) or data streams, so unlike
+          // the MKV-safe blanket "-map 0" above, restrict this output to audio only
+          // (negative maps drop the subtitle and attachment streams "-map 0" added).
+          encode_audio.args(["-map", "-0:s", "-map", "-0:t", "-c:a", "flac", "-movflags", "+frag_keyframe+empty_moov+default_base_moof"]);
+        },
+        AudioEncodeMode::Mkv => { encode_audio.args(audio_params); },
     };
-    
+
     encode_audio.arg(&audio_file);
 
     let output = encode_audio.output().unwrap();
@@ -294,17 +563,21 @@ pub fn encode_audio<S: AsRef<OsStr>>(
         output, encode_audio
       );
       return None;
-    } else if opus_mode {
-      handle_opus(
-        input, 
-        &audio_file, 
-        Path::new(temp).join("audio.mkv").as_path(),
-        temp
-      );
+    }
 
-      Some(Path::new(temp).join("audio.mkv"))
-    } else {
-      Some(audio_file)
+    match mode {
+      AudioEncodeMode::Opus { mono_streams } => {
+        handle_opus(
+          input,
+          &audio_file,
+          Path::new(temp).join("audio.mkv").as_path(),
+          temp,
+          mono_streams,
+        );
+
+        Some(Path::new(temp).join("audio.mkv"))
+      },
+      AudioEncodeMode::FlacMp4 | AudioEncodeMode::Mkv => Some(audio_file),
     }
   } else {
     None
@@ -333,3 +606,27 @@ pub fn escape_path_in_filter(path: impl AsRef<Path>) -> String {
   .replace(']', r"\]")
   .replace(',', "\\,")
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn layout(channels: i32, has_lfe: bool) -> ChannelLayoutInfo {
+    ChannelLayoutInfo { name: String::new(), channels, has_lfe, channel_labels: Vec::new() }
+  }
+
+  #[test]
+  fn opus_bitrate_for_mono() {
+    assert_eq!(opus_bitrate_for(&layout(1, false)), 76);
+  }
+
+  #[test]
+  fn opus_bitrate_for_stereo() {
+    assert_eq!(opus_bitrate_for(&layout(2, false)), 128);
+  }
+
+  #[test]
+  fn opus_bitrate_for_5_1() {
+    assert_eq!(opus_bitrate_for(&layout(6, true)), 258);
+  }
+}
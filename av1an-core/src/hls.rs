@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::ffmpeg::{frame_rate, get_keyframes};
+
+/// One HLS/fMP4 segment: start frame (always a keyframe), start time, and duration.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+  pub start_frame: usize,
+  pub start_time: f64,
+  pub duration: f64,
+}
+
+/// Splits `[0, total_frames)` into segments close to `target_duration` seconds
+/// long, snapping every boundary to the next keyframe. `keyframes` must be
+/// sorted ascending, as returned by [`get_keyframes`].
+pub fn compute_segments(keyframes: &[usize], total_frames: usize, frame_rate: f64, target_duration: f64) -> Vec<Segment> {
+  if keyframes.is_empty() || total_frames == 0 {
+    return Vec::new();
+  }
+
+  let target_frames = (target_duration * frame_rate).round().max(1.0) as usize;
+
+  let mut boundaries = vec![0usize];
+  let mut next_target = target_frames;
+
+  for &kf in keyframes.iter().filter(|&&kf| kf > 0) {
+    if kf >= next_target {
+      boundaries.push(kf);
+      next_target = kf + target_frames;
+    }
+  }
+
+  let mut segments: Vec<Segment> = boundaries
+    .windows(2)
+    .map(|w| Segment {
+      start_frame: w[0],
+      start_time: w[0] as f64 / frame_rate,
+      duration: (w[1] - w[0]) as f64 / frame_rate,
+    })
+    .collect();
+
+  let last = *boundaries.last().unwrap();
+  if last < total_frames {
+    segments.push(Segment {
+      start_frame: last,
+      start_time: last as f64 / frame_rate,
+      duration: (total_frames - last) as f64 / frame_rate,
+    });
+  }
+
+  segments
+}
+
+/// Computes a [`Segment`] plan for `source`, targeting segments of
+/// `target_duration` seconds snapped to `source`'s own keyframes.
+pub fn compute_segments_for(source: &Path, total_frames: usize, target_duration: f64) -> Result<Vec<Segment>, ffmpeg::Error> {
+  let keyframes = get_keyframes(source)?;
+  let fps = frame_rate(source)?;
+  Ok(compute_segments(&keyframes, total_frames, fps, target_duration))
+}
+
+/// Muxes `video` (and `audio`, if present) into keyframe-aligned fMP4
+/// segments plus an HLS `.m3u8` playlist under `out_dir`.
+///
+/// This uses FFmpeg's own `hls` muxer (stream copy), which snaps each cut to
+/// the next keyframe at or after `target_duration` seconds — the same rule
+/// `segments` was computed with, so its boundaries already match what gets
+/// produced. `init.mp4` carries the shared `moov`, with every `seg_NNNNN.m4s`
+/// a numbered fragment referencing it via `EXT-X-MAP`. `segments`' own
+/// durations are then patched into the playlist's `#EXTINF` entries so they
+/// reflect each segment's real, keyframe-snapped duration exactly.
+pub fn mux_hls_fmp4(video: &Path, audio: Option<&Path>, out_dir: &Path, target_duration: f64, segments: &[Segment]) -> std::io::Result<PathBuf> {
+  std::fs::create_dir_all(out_dir)?;
+
+  let playlist = out_dir.join("stream.m3u8");
+
+  let mut cmd = Command::new("ffmpeg");
+  cmd.args(["-y", "-hide_banner", "-loglevel", "error", "-i"]);
+  cmd.arg(video);
+
+  if let Some(audio) = audio {
+    cmd.arg("-i").arg(audio);
+    cmd.args(["-map", "0:v:0", "-map", "1:a:0"]);
+  } else {
+    cmd.args(["-map", "0:v:0"]);
+  }
+
+  cmd.args(["-c", "copy"]);
+  cmd.args(["-f", "hls"]);
+  cmd.args(["-hls_time", &target_duration.to_string()]);
+  cmd.args(["-hls_segment_type", "fmp4"]);
+  cmd.args(["-hls_fmp4_init_filename", "init.mp4"]);
+  cmd.args(["-hls_flags", "independent_segments"]);
+  cmd.args(["-hls_segment_filename", &out_dir.join("seg_%05d.m4s").to_string_lossy()]);
+  cmd.arg(&playlist);
+
+  let output = cmd.output()?;
+
+  if !output.status.success() {
+    warn!("FFmpeg failed to package HLS output!\n{:#?}\nParams: {:?}", output, cmd);
+    return Err(std::io::Error::new(std::io::ErrorKind::Other, "ffmpeg hls packaging failed"));
+  }
+
+  patch_extinf_durations(&playlist, segments)?;
+
+  Ok(playlist)
+}
+
+/// Rewrites every `#EXTINF` entry in `playlist`, in order, with the matching
+/// [`Segment`]'s real duration, so the playlist reflects `segments` exactly
+/// rather than whatever FFmpeg's muxer happened to compute internally.
+fn patch_extinf_durations(playlist: &Path, segments: &[Segment]) -> std::io::Result<()> {
+  let contents = std::fs::read_to_string(playlist)?;
+  let mut durations = segments.iter().map(|s| s.duration);
+
+  let patched: Vec<String> = contents
+    .lines()
+    .map(|line| {
+      if line.starts_with("#EXTINF:") {
+        match durations.next() {
+          Some(duration) => format!("#EXTINF:{duration:.6},"),
+          None => line.to_owned(),
+        }
+      } else {
+        line.to_owned()
+      }
+    })
+    .collect();
+
+  std::fs::write(playlist, patched.join("\n") + "\n")
+}
+
+/// Computes the keyframe-aligned segment plan for `video` and muxes it into
+/// an fMP4/HLS package under `out_dir`.
+pub fn package_hls(
+  video: &Path,
+  audio: Option<&Path>,
+  out_dir: &Path,
+  total_frames: usize,
+  target_duration: f64,
+) -> std::io::Result<PathBuf> {
+  let segments = compute_segments_for(video, total_frames, target_duration)
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+
+  mux_hls_fmp4(video, audio, out_dir, target_duration, &segments)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn compute_segments_snaps_to_keyframes() {
+    let segments = compute_segments(&[0, 30, 60, 90], 100, 30.0, 1.0);
+
+    assert_eq!(segments.len(), 4);
+    assert_eq!(segments.iter().map(|s| s.start_frame).collect::<Vec<_>>(), vec![0, 30, 60, 90]);
+    assert!((segments[0].duration - 1.0).abs() < 1e-9);
+    assert!((segments[3].duration - 10.0 / 30.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn compute_segments_empty_input() {
+    assert!(compute_segments(&[], 100, 30.0, 1.0).is_empty());
+    assert!(compute_segments(&[0, 30], 0, 30.0, 1.0).is_empty());
+  }
+}
@@ -0,0 +1,57 @@
+use clap::Args;
+
+use crate::ffmpeg::AudioEncodeMode;
+
+/// CLI-facing audio encoding flags, bridging user input to
+/// [`AudioEncodeMode`]. The default keeps the existing "keep tracks intact"
+/// (`Mkv`) behavior, so opus/FLAC re-encoding is always an explicit opt-in.
+#[derive(Args, Debug, Clone, Copy, Default)]
+pub struct AudioArgs {
+  /// Re-encode every audio track to opus instead of keeping it as-is.
+  #[clap(long)]
+  pub opus: bool,
+
+  /// Re-encode every audio track to FLAC in an MP4 container instead of
+  /// keeping it as-is. Takes priority over `--opus` if both are given.
+  #[clap(long)]
+  pub flac_mp4: bool,
+
+  /// Split each multichannel opus track into labeled mono streams instead
+  /// of keeping it as a single multichannel track. Only applies with
+  /// `--opus`.
+  #[clap(long, requires = "opus")]
+  pub mono_streams: bool,
+}
+
+impl From<AudioArgs> for AudioEncodeMode {
+  fn from(args: AudioArgs) -> Self {
+    if args.flac_mp4 {
+      Self::FlacMp4
+    } else if args.opus {
+      Self::Opus { mono_streams: args.mono_streams }
+    } else {
+      Self::Mkv
+    }
+  }
+}
+
+/// CLI-facing HLS/fMP4 packaging flags. `--hls` is off by default, so output
+/// stays a single muxed file unless a caller opts in.
+#[derive(Args, Debug, Clone, Copy)]
+pub struct HlsArgs {
+  /// Package the output as keyframe-aligned fMP4 segments plus an HLS
+  /// `.m3u8` playlist instead of a single muxed file.
+  #[clap(long)]
+  pub hls: bool,
+
+  /// Target duration, in seconds, of each HLS segment. Segments are snapped
+  /// to the next keyframe at or after this length, so real durations vary.
+  #[clap(long, default_value_t = 6.0, requires = "hls")]
+  pub segment_duration: f64,
+}
+
+impl Default for HlsArgs {
+  fn default() -> Self {
+    Self { hls: false, segment_duration: 6.0 }
+  }
+}